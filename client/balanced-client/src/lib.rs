@@ -0,0 +1,429 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! A [`ClientT`] implementation that fans a request out to a pool of backends, classifies
+//! upstream rate-limit errors, and fails over to the next healthy backend.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use jsonrpsee_core::client::{BatchResponse, ClientT};
+use jsonrpsee_core::params::BatchRequestBuilder;
+use jsonrpsee_core::traits::ToRpcParams;
+use jsonrpsee_core::Error;
+use jsonrpsee_types::ErrorObjectOwned;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+
+/// Rules used to classify an [`ErrorObjectOwned`] returned by a backend as a rate-limit signal
+/// rather than a hard failure.
+#[derive(Debug, Clone)]
+pub struct RateLimitClassifier {
+	/// Case-insensitive substrings that, if present in the error message, mark the backend as
+	/// rate-limited.
+	pub deny_patterns: Vec<String>,
+	/// Case-insensitive substrings that override a `deny_patterns` match, e.g. payload-size
+	/// errors that merely contain the word "limit".
+	pub allow_patterns: Vec<String>,
+}
+
+impl Default for RateLimitClassifier {
+	fn default() -> Self {
+		Self {
+			deny_patterns: vec!["limit".into(), "exceeded".into(), "quota usage".into()],
+			allow_patterns: vec!["result exceeds length limit".into()],
+		}
+	}
+}
+
+impl RateLimitClassifier {
+	/// Whether `err` should be treated as a rate-limit signal rather than a hard error.
+	pub fn is_rate_limited(&self, err: &ErrorObjectOwned) -> bool {
+		let msg = err.message().to_lowercase();
+
+		if self.allow_patterns.iter().any(|pat| msg.contains(&pat.to_lowercase())) {
+			return false;
+		}
+
+		self.deny_patterns.iter().any(|pat| msg.contains(&pat.to_lowercase()))
+	}
+}
+
+/// Consecutive hard failures (errors that aren't rate-limit signals) after which a backend is
+/// marked dead and demoted to the back of [`BalancedClient::preference_order`], until a
+/// subsequent successful call revives it.
+const DEAD_AFTER_CONSECUTIVE_FAILURES: u32 = 5;
+
+struct BackendHealth {
+	cooled_down_until: Mutex<Option<Instant>>,
+	avg_latency: AtomicU64,
+	consecutive_failures: AtomicU64,
+	alive: AtomicBool,
+}
+
+impl Default for BackendHealth {
+	fn default() -> Self {
+		Self {
+			cooled_down_until: Mutex::new(None),
+			avg_latency: AtomicU64::new(0),
+			consecutive_failures: AtomicU64::new(0),
+			alive: AtomicBool::new(true),
+		}
+	}
+}
+
+impl BackendHealth {
+	fn is_cooled_down(&self) -> bool {
+		match *self.cooled_down_until.lock() {
+			Some(until) => Instant::now() < until,
+			None => false,
+		}
+	}
+
+	fn cool_down(&self, window: Duration) {
+		*self.cooled_down_until.lock() = Some(Instant::now() + window);
+	}
+
+	fn record_latency(&self, elapsed: Duration) {
+		// Exponential moving average, kept in whole microseconds.
+		let sample = elapsed.as_micros() as u64;
+		let prev = self.avg_latency.load(Ordering::Relaxed);
+		let next = if prev == 0 { sample } else { (prev * 3 + sample) / 4 };
+		self.avg_latency.store(next, Ordering::Relaxed);
+	}
+
+	/// Reset the consecutive-hard-failure streak after a successful call, reviving the backend if
+	/// it had previously been marked dead.
+	fn record_success(&self) {
+		self.consecutive_failures.store(0, Ordering::Relaxed);
+		self.alive.store(true, Ordering::Relaxed);
+	}
+
+	/// Record a hard failure, marking the backend dead once
+	/// [`DEAD_AFTER_CONSECUTIVE_FAILURES`] have been seen in a row.
+	fn record_hard_failure(&self) {
+		let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+		if failures >= DEAD_AFTER_CONSECUTIVE_FAILURES as u64 {
+			self.alive.store(false, Ordering::Relaxed);
+		}
+	}
+}
+
+/// One backend in a [`BalancedClient`] pool.
+struct Backend<C> {
+	client: C,
+	health: BackendHealth,
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A [`ClientT`] that forwards requests to a pool of backends, retrying against the next healthy
+/// one on failure and cooling down backends that report rate-limit errors instead of treating
+/// them as hard failures.
+///
+/// Generic over a single concrete backend type `C` rather than `Box<dyn ClientT>`: `ClientT`'s
+/// `request`/`batch_request` are generic over their response type, which makes the trait not
+/// object-safe.
+pub struct BalancedClient<C> {
+	backends: Vec<Backend<C>>,
+	classifier: RateLimitClassifier,
+	cooldown: Duration,
+}
+
+impl<C: ClientT + Send + Sync> BalancedClient<C> {
+	/// Create a new pool from `backends`, using the default [`RateLimitClassifier`] and a
+	/// `cooldown` window applied to backends classified as rate-limited.
+	pub fn new(backends: Vec<C>, cooldown: Duration) -> Self {
+		Self::with_classifier(backends, cooldown, RateLimitClassifier::default())
+	}
+
+	/// Like [`BalancedClient::new`] but with a custom [`RateLimitClassifier`].
+	pub fn with_classifier(backends: Vec<C>, cooldown: Duration, classifier: RateLimitClassifier) -> Self {
+		let backends = backends.into_iter().map(|client| Backend { client, health: BackendHealth::default() }).collect();
+		Self { backends, classifier, cooldown }
+	}
+
+	/// Indices of every backend, ordered by preference: alive non-cooled-down backends first,
+	/// lowest latency first, dead ones last of all. Dead backends are demoted rather than
+	/// excluded so a request still has somewhere to go - and a real error to report - if every
+	/// other backend is also down, and so a dead backend gets a chance to prove itself recovered
+	/// instead of being shut out for the client's entire lifetime.
+	fn preference_order(&self) -> Vec<usize> {
+		let mut order: Vec<usize> = (0..self.backends.len()).collect();
+
+		order.sort_by_key(|&i| {
+			let b = &self.backends[i];
+			(!b.health.alive.load(Ordering::Relaxed), b.health.is_cooled_down(), b.health.avg_latency.load(Ordering::Relaxed))
+		});
+
+		order
+	}
+
+	/// Try `call` against every backend in [`Self::preference_order`], applying the same
+	/// rate-limit classification, cooldown and latency tracking regardless of which [`ClientT`]
+	/// method `call` wraps - this is what every one of `notification`, `request` and
+	/// `batch_request` funnels through, instead of only `request` honoring the classifier.
+	async fn run_with_failover<'c, T, F>(&'c self, mut call: F) -> Result<T, Error>
+	where
+		F: FnMut(&'c C) -> BoxFuture<'c, Result<T, Error>>,
+	{
+		let mut last_err = None;
+
+		for idx in self.preference_order() {
+			let backend = &self.backends[idx];
+			let start = Instant::now();
+
+			match call(&backend.client).await {
+				Ok(rp) => {
+					backend.health.record_latency(start.elapsed());
+					backend.health.record_success();
+					return Ok(rp);
+				}
+				Err(Error::Call(err)) if self.classifier.is_rate_limited(&err) => {
+					backend.health.cool_down(self.cooldown);
+					last_err = Some(Error::Call(err));
+				}
+				Err(err) => {
+					backend.health.record_hard_failure();
+					last_err = Some(err);
+				}
+			}
+		}
+
+		Err(last_err.unwrap_or_else(|| Error::Custom("no healthy backend available".into())))
+	}
+}
+
+#[async_trait::async_trait]
+impl<C: ClientT + Send + Sync> ClientT for BalancedClient<C> {
+	async fn notification(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<(), Error> {
+		self.run_with_failover(move |backend| Box::pin(backend.notification(method, params.clone()))).await
+	}
+
+	async fn request<R>(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<R, Error>
+	where
+		R: DeserializeOwned,
+	{
+		self.run_with_failover(move |backend| Box::pin(backend.request(method, params.clone()))).await
+	}
+
+	async fn batch_request<'a, R>(&self, batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, Error>
+	where
+		R: DeserializeOwned + std::fmt::Debug + 'a,
+	{
+		self.run_with_failover(move |backend| Box::pin(backend.batch_request(batch.clone()))).await
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::atomic::AtomicUsize;
+	use std::sync::Arc;
+
+	/// A fake backend whose `request`/`notification` outcome is scripted up front, so tests can
+	/// assert failover/cooldown ordering without a real transport.
+	struct FakeBackend {
+		calls: Arc<AtomicUsize>,
+		outcome: Result<serde_json::Value, ErrorObjectOwned>,
+	}
+
+	#[async_trait::async_trait]
+	impl ClientT for FakeBackend {
+		async fn notification(&self, _method: &str, _params: impl ToRpcParams + Send + Clone) -> Result<(), Error> {
+			self.calls.fetch_add(1, Ordering::Relaxed);
+			match &self.outcome {
+				Ok(_) => Ok(()),
+				Err(err) => Err(Error::Call(err.clone())),
+			}
+		}
+
+		async fn request<R>(&self, _method: &str, _params: impl ToRpcParams + Send + Clone) -> Result<R, Error>
+		where
+			R: DeserializeOwned,
+		{
+			self.calls.fetch_add(1, Ordering::Relaxed);
+			match &self.outcome {
+				Ok(value) => serde_json::from_value(value.clone()).map_err(|e| Error::Custom(e.to_string())),
+				Err(err) => Err(Error::Call(err.clone())),
+			}
+		}
+
+		async fn batch_request<'a, R>(&self, _batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, Error>
+		where
+			R: DeserializeOwned + std::fmt::Debug + 'a,
+		{
+			self.calls.fetch_add(1, Ordering::Relaxed);
+			match &self.outcome {
+				Ok(_) => Ok(BatchResponse::new(Vec::new())),
+				Err(err) => Err(Error::Call(err.clone())),
+			}
+		}
+	}
+
+	fn rate_limited_backend(calls: Arc<AtomicUsize>) -> FakeBackend {
+		FakeBackend { calls, outcome: Err(ErrorObjectOwned::owned(-32005, "rate limit exceeded", None)) }
+	}
+
+	fn healthy_backend(calls: Arc<AtomicUsize>) -> FakeBackend {
+		FakeBackend { calls, outcome: Ok(serde_json::json!(1)) }
+	}
+
+	#[test]
+	fn classifier_allow_pattern_overrides_deny_pattern() {
+		let classifier = RateLimitClassifier::default();
+		let allowed = ErrorObjectOwned::owned(-32000, "result exceeds length limit", None);
+		let denied = ErrorObjectOwned::owned(-32000, "rate limit exceeded", None);
+		let unrelated = ErrorObjectOwned::owned(-32000, "method not found", None);
+
+		assert!(!classifier.is_rate_limited(&allowed));
+		assert!(classifier.is_rate_limited(&denied));
+		assert!(!classifier.is_rate_limited(&unrelated));
+	}
+
+	#[tokio::test]
+	async fn request_fails_over_to_the_next_healthy_backend_and_cools_down_the_rate_limited_one() {
+		let limited_calls = Arc::new(AtomicUsize::new(0));
+		let healthy_calls = Arc::new(AtomicUsize::new(0));
+
+		let client = BalancedClient::new(
+			vec![rate_limited_backend(limited_calls.clone()), healthy_backend(healthy_calls.clone())],
+			Duration::from_secs(30),
+		);
+
+		let result: Result<u64, _> = client.request("x", ()).await;
+		assert_eq!(result.unwrap(), 1);
+		assert_eq!(limited_calls.load(Ordering::Relaxed), 1);
+		assert_eq!(healthy_calls.load(Ordering::Relaxed), 1);
+		assert!(client.backends[0].health.is_cooled_down());
+	}
+
+	#[tokio::test]
+	async fn notification_honors_the_classifier_and_cooldown_like_request_does() {
+		let limited_calls = Arc::new(AtomicUsize::new(0));
+		let healthy_calls = Arc::new(AtomicUsize::new(0));
+
+		let client = BalancedClient::new(
+			vec![rate_limited_backend(limited_calls.clone()), healthy_backend(healthy_calls.clone())],
+			Duration::from_secs(30),
+		);
+
+		client.notification("x", ()).await.unwrap();
+		assert!(client.backends[0].health.is_cooled_down());
+		assert_eq!(healthy_calls.load(Ordering::Relaxed), 1);
+	}
+
+	#[tokio::test]
+	async fn batch_request_honors_the_classifier_instead_of_swallowing_the_real_error() {
+		let limited_calls = Arc::new(AtomicUsize::new(0));
+		let healthy_calls = Arc::new(AtomicUsize::new(0));
+
+		let client = BalancedClient::new(
+			vec![rate_limited_backend(limited_calls.clone()), healthy_backend(healthy_calls.clone())],
+			Duration::from_secs(30),
+		);
+
+		let batch = BatchRequestBuilder::new();
+		let result: Result<BatchResponse<'_, u64>, _> = client.batch_request(batch).await;
+		assert!(result.is_ok());
+		assert!(client.backends[0].health.is_cooled_down());
+		assert_eq!(healthy_calls.load(Ordering::Relaxed), 1);
+	}
+
+	#[tokio::test]
+	async fn all_backends_failing_propagates_the_real_last_error() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		let client = BalancedClient::new(
+			vec![FakeBackend { calls: calls.clone(), outcome: Err(ErrorObjectOwned::owned(-32000, "boom", None)) }],
+			Duration::from_secs(30),
+		);
+
+		let result: Result<u64, _> = client.request("x", ()).await;
+		match result {
+			Err(Error::Call(err)) => assert_eq!(err.message(), "boom"),
+			other => panic!("expected the real backend error to propagate, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn backend_is_marked_dead_and_demoted_after_enough_consecutive_hard_failures() {
+		let failing_calls = Arc::new(AtomicUsize::new(0));
+		let healthy_calls = Arc::new(AtomicUsize::new(0));
+
+		let client = BalancedClient::new(
+			vec![
+				FakeBackend { calls: failing_calls.clone(), outcome: Err(ErrorObjectOwned::owned(-32000, "boom", None)) },
+				healthy_backend(healthy_calls.clone()),
+			],
+			Duration::from_secs(30),
+		);
+
+		for _ in 0..DEAD_AFTER_CONSECUTIVE_FAILURES {
+			let _: Result<u64, _> = client.request("x", ()).await;
+		}
+		assert!(!client.backends[0].health.alive.load(Ordering::Relaxed));
+
+		// Once dead, the backend is demoted to the back of preference order rather than removed,
+		// so it's only tried as a last resort.
+		assert_eq!(client.preference_order(), vec![1, 0]);
+	}
+
+	#[tokio::test]
+	async fn a_dead_backend_still_surfaces_its_real_error_if_every_backend_is_dead() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		let client = BalancedClient::new(
+			vec![FakeBackend { calls: calls.clone(), outcome: Err(ErrorObjectOwned::owned(-32000, "boom", None)) }],
+			Duration::from_secs(30),
+		);
+
+		for _ in 0..DEAD_AFTER_CONSECUTIVE_FAILURES {
+			let _: Result<u64, _> = client.request("x", ()).await;
+		}
+		assert!(!client.backends[0].health.alive.load(Ordering::Relaxed));
+
+		// Even though the only backend is dead, it's still tried - and the real error still
+		// propagates - instead of every call failing with a generic "no healthy backend" message.
+		let result: Result<u64, _> = client.request("x", ()).await;
+		match result {
+			Err(Error::Call(err)) => assert_eq!(err.message(), "boom"),
+			other => panic!("expected the real backend error to propagate, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn a_dead_backend_is_revived_by_a_subsequent_successful_call() {
+		let calls = Arc::new(AtomicUsize::new(0));
+		// A healthy backend, but pre-marked dead to simulate one that has since recovered.
+		let client = BalancedClient::new(vec![healthy_backend(calls.clone())], Duration::from_secs(30));
+		client.backends[0].health.alive.store(false, Ordering::Relaxed);
+
+		let result: Result<u64, _> = client.request("x", ()).await;
+		assert_eq!(result.unwrap(), 1);
+		assert!(client.backends[0].health.alive.load(Ordering::Relaxed));
+	}
+}
@@ -0,0 +1,227 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! `jsonrpsee` WebSocket client.
+
+mod reconnect;
+mod transport;
+
+pub use reconnect::{ReconnectEvent, ReconnectState, RetryPolicy};
+
+use jsonrpsee_core::client::{BatchResponse, ClientT, Subscription};
+use jsonrpsee_core::params::BatchRequestBuilder;
+use jsonrpsee_core::traits::ToRpcParams;
+use jsonrpsee_core::async_trait;
+use reconnect::ReconnectPublisher;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fmt::Debug;
+use tokio::sync::{mpsc, oneshot};
+use transport::Command;
+
+/// Error type returned by [`WsClient`].
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+	/// The transport was lost while this request was in flight and the request was not resent:
+	/// either it was not marked idempotent (see [`WsClient::request_idempotent`]), or redialing
+	/// gave up or the resend failed. The caller owns the decision of whether to resubmit the
+	/// request.
+	#[error("the connection was lost while this request was in flight")]
+	Reconnected,
+
+	/// A transport-level failure (dial failed, send failed, background driver task is gone, ...).
+	#[error("transport error: {0}")]
+	Transport(String),
+
+	/// Any other client-level error, forwarded from [`jsonrpsee_core::Error`].
+	#[error(transparent)]
+	Core(#[from] jsonrpsee_core::Error),
+}
+
+/// Builder for [`WsClient`].
+#[derive(Clone, Default)]
+pub struct WsClientBuilder {
+	reconnect: Option<RetryPolicy>,
+}
+
+impl WsClientBuilder {
+	/// Create a new builder with default settings.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Enable auto-reconnect: on transport failure the background driver task transparently
+	/// redials per `policy`, re-issues every still-referenced subscription and rebinds the new
+	/// notification stream to the existing [`Subscription`] handle so consumers keep receiving
+	/// items across the gap.
+	///
+	/// Requests that were in flight at disconnect time are either retried, if idempotent, or
+	/// resolved with [`Error::Reconnected`] so the caller can decide what to do ([`ClientT::request`]
+	/// surfaces it as a [`jsonrpsee_core::Error::Custom`] carrying the same message). Use
+	/// [`WsClient::reconnect_state`] to observe reconnect events and count how many times the
+	/// socket has flapped.
+	pub fn with_reconnect(mut self, policy: RetryPolicy) -> Self {
+		self.reconnect = Some(policy);
+		self
+	}
+
+	/// Build the client, connecting to `url`.
+	pub async fn build(self, url: impl AsRef<str>) -> Result<WsClient, Error> {
+		WsClient::connect(url.as_ref(), self.reconnect).await
+	}
+}
+
+/// A WebSocket client driven by a background task that owns the transport.
+///
+/// When built with [`WsClientBuilder::with_reconnect`], the driver task survives a transport
+/// failure by redialing and re-subscribing instead of tearing the client down.
+pub struct WsClient {
+	to_driver: mpsc::UnboundedSender<Command>,
+	reconnect_state: Option<ReconnectState>,
+}
+
+impl WsClient {
+	async fn connect(url: &str, reconnect: Option<RetryPolicy>) -> Result<Self, Error> {
+		// Dial once up front so `build()` fails fast on a bad address instead of handing back a
+		// client whose very first call discovers the server doesn't exist - and hand the resulting
+		// stream to the driver task rather than dialing a second connection there.
+		let stream = transport::dial(url).await.map_err(Error::Transport)?;
+
+		let (to_driver, cmd_rx) = mpsc::unbounded_channel();
+		let (publisher, reconnect_state) = ReconnectPublisher::new();
+		let reconnect_state = reconnect.is_some().then_some(reconnect_state);
+
+		let url = url.to_owned();
+		tokio::spawn(transport::run(url, stream, cmd_rx, reconnect, publisher));
+
+		Ok(Self { to_driver, reconnect_state })
+	}
+
+	/// Whether the background driver task is still alive, i.e. the client handle can still send
+	/// commands. A client built without [`WsClientBuilder::with_reconnect`] stops being connected
+	/// the moment the transport drops; one built with it stays connected across a redial.
+	pub fn is_connected(&self) -> bool {
+		!self.to_driver.is_closed()
+	}
+
+	/// Observe reconnect events, if this client was built with [`WsClientBuilder::with_reconnect`].
+	pub fn reconnect_state(&self) -> Option<ReconnectState> {
+		self.reconnect_state.clone()
+	}
+
+	/// Same as [`ClientT::request`], but marks the call as safe to silently retry against the
+	/// redialed connection rather than being resolved with [`Error::Reconnected`] if the
+	/// transport drops while it's in flight.
+	pub async fn request_idempotent<R>(&self, method: &str, params: impl ToRpcParams + Send) -> Result<R, Error>
+	where
+		R: DeserializeOwned,
+	{
+		self.call(method, params, true).await
+	}
+
+	async fn call<R>(&self, method: &str, params: impl ToRpcParams + Send, idempotent: bool) -> Result<R, Error>
+	where
+		R: DeserializeOwned,
+	{
+		let params = params.to_rpc_params().map_err(|e| Error::Transport(e.to_string()))?.map(|raw| serde_json::from_str::<Value>(raw.get())).transpose().map_err(|e| Error::Transport(e.to_string()))?;
+
+		let (respond, rx) = oneshot::channel();
+		self.to_driver
+			.send(Command::Call { method: method.to_owned(), params, idempotent, respond })
+			.map_err(|_| Error::Transport("background driver task is gone".into()))?;
+
+		let value = rx.await.map_err(|_| Error::Transport("background driver task is gone".into()))??;
+		serde_json::from_value(value).map_err(|e| Error::Transport(e.to_string()))
+	}
+
+	/// Subscribe to `subscribe_method`, returning a [`Subscription`] that yields every
+	/// notification pushed for it until dropped - including across a redial, if this client was
+	/// built with [`WsClientBuilder::with_reconnect`].
+	pub async fn subscribe<Notif>(&self, subscribe_method: &str, params: impl ToRpcParams + Send) -> Result<Subscription<Notif>, Error>
+	where
+		Notif: DeserializeOwned + Send + 'static,
+	{
+		let params = params.to_rpc_params().map_err(|e| Error::Transport(e.to_string()))?.map(|raw| serde_json::from_str::<Value>(raw.get())).transpose().map_err(|e| Error::Transport(e.to_string()))?;
+
+		let (tx, rx) = mpsc::channel(64);
+		let (respond, ack) = oneshot::channel();
+		self.to_driver
+			.send(Command::Subscribe { method: subscribe_method.to_owned(), params, tx, respond })
+			.map_err(|_| Error::Transport("background driver task is gone".into()))?;
+
+		ack.await.map_err(|_| Error::Transport("background driver task is gone".into()))??;
+
+		let (out_tx, out_rx) = mpsc::channel(64);
+		tokio::spawn(forward_typed::<Notif>(rx, out_tx));
+		Ok(Subscription::new(out_rx))
+	}
+}
+
+/// Deserialize each raw [`Value`] pushed by the driver task into `Notif` before handing it to the
+/// caller's [`Subscription`], so a malformed push is dropped instead of poisoning the channel.
+async fn forward_typed<Notif: DeserializeOwned + Send + 'static>(mut rx: mpsc::Receiver<Value>, tx: mpsc::Sender<Notif>) {
+	while let Some(value) = rx.recv().await {
+		if let Ok(notif) = serde_json::from_value(value) {
+			if tx.send(notif).await.is_err() {
+				break;
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl ClientT for WsClient {
+	async fn notification(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<(), jsonrpsee_core::Error> {
+		let params = params.to_rpc_params().map_err(|e| jsonrpsee_core::Error::Custom(e.to_string()))?.map(|raw| serde_json::from_str::<Value>(raw.get())).transpose().map_err(|e| jsonrpsee_core::Error::Custom(e.to_string()))?;
+
+		self.to_driver
+			.send(Command::Notification { method: method.to_owned(), params })
+			.map_err(|_| jsonrpsee_core::Error::Transport("background driver task is gone".into()))
+	}
+
+	async fn request<R>(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<R, jsonrpsee_core::Error>
+	where
+		R: DeserializeOwned,
+	{
+		self.call(method, params, false).await.map_err(to_core_error)
+	}
+
+	async fn batch_request<'a, R>(&self, batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, jsonrpsee_core::Error>
+	where
+		R: DeserializeOwned + Debug + 'a,
+	{
+		let _ = batch;
+		Err(jsonrpsee_core::Error::Custom("batch requests are not yet supported over this transport".into()))
+	}
+}
+
+fn to_core_error(e: Error) -> jsonrpsee_core::Error {
+	match e {
+		Error::Core(core_err) => core_err,
+		Error::Reconnected => jsonrpsee_core::Error::Custom(Error::Reconnected.to_string()),
+		Error::Transport(msg) => jsonrpsee_core::Error::Transport(msg),
+	}
+}
@@ -0,0 +1,179 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Opt-in auto-reconnect for [`WsClient`](crate::WsClient).
+//!
+//! Enabled via [`WsClientBuilder::with_reconnect`](crate::WsClientBuilder::with_reconnect), this
+//! keeps the background driver task alive across a dropped transport: it redials with backoff,
+//! re-issues every still-referenced subscription and rebinds the new notification stream to the
+//! caller's existing [`Subscription`](jsonrpsee_core::client::Subscription) handle, instead of
+//! requiring the caller to rebuild the client from scratch.
+
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::watch;
+
+/// Backoff policy used to space out redial attempts after a transport failure.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+	/// Delay before the first redial attempt.
+	pub initial_delay: Duration,
+	/// Upper bound the exponential backoff is capped at.
+	pub max_delay: Duration,
+	/// Multiplier applied to the delay after every failed attempt.
+	pub multiplier: f64,
+	/// Maximum number of redial attempts; `None` retries forever.
+	pub max_attempts: Option<usize>,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			initial_delay: Duration::from_millis(200),
+			max_delay: Duration::from_secs(30),
+			multiplier: 2.0,
+			max_attempts: None,
+		}
+	}
+}
+
+impl RetryPolicy {
+	/// Compute the delay before redial attempt number `attempt` (0-indexed), with full jitter.
+	pub fn delay_for(&self, attempt: usize) -> Duration {
+		let unjittered = self.initial_delay.as_secs_f64() * self.multiplier.powi(attempt as i32);
+		let capped = unjittered.min(self.max_delay.as_secs_f64());
+		let jittered = rand::thread_rng().gen_range(0.0..=capped);
+		Duration::from_secs_f64(jittered)
+	}
+
+	/// Whether another redial attempt is permitted after `attempts_so_far` failed attempts.
+	pub fn should_retry(&self, attempts_so_far: usize) -> bool {
+		self.max_attempts.is_none_or(|max| attempts_so_far < max)
+	}
+}
+
+/// A single reconnect event, published on the [`ReconnectState`] watch channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconnectEvent {
+	/// The transport was lost and a redial loop has started.
+	Disconnected,
+	/// A redial attempt is about to be made.
+	Reconnecting { attempt: usize },
+	/// The transport was redialed and a subscribe request was sent again for every active
+	/// subscription; the responses to those requests are not awaited first. `flaps` counts the
+	/// redials so far and is `0` in the value the channel starts with.
+	Reconnected { flaps: usize },
+}
+
+/// Observable reconnect state for a [`WsClient`](crate::WsClient) built with
+/// [`WsClientBuilder::with_reconnect`](crate::WsClientBuilder::with_reconnect).
+#[derive(Clone)]
+pub struct ReconnectState {
+	rx: watch::Receiver<ReconnectEvent>,
+}
+
+impl ReconnectState {
+	pub(crate) fn new(rx: watch::Receiver<ReconnectEvent>) -> Self {
+		Self { rx }
+	}
+
+	/// Wait for the next reconnect event.
+	pub async fn changed(&mut self) -> Result<ReconnectEvent, watch::error::RecvError> {
+		self.rx.changed().await?;
+		Ok(*self.rx.borrow())
+	}
+
+	/// The most recently observed reconnect event.
+	pub fn current(&self) -> ReconnectEvent {
+		*self.rx.borrow()
+	}
+}
+
+/// Handle used internally by the background driver task to publish [`ReconnectEvent`]s and track
+/// how many times the socket has flapped.
+pub(crate) struct ReconnectPublisher {
+	tx: watch::Sender<ReconnectEvent>,
+	flaps: usize,
+}
+
+impl ReconnectPublisher {
+	pub(crate) fn new() -> (Self, ReconnectState) {
+		let (tx, rx) = watch::channel(ReconnectEvent::Reconnected { flaps: 0 });
+		(Self { tx, flaps: 0 }, ReconnectState::new(rx))
+	}
+
+	pub(crate) fn disconnected(&self) {
+		let _ = self.tx.send(ReconnectEvent::Disconnected);
+	}
+
+	pub(crate) fn reconnecting(&self, attempt: usize) {
+		let _ = self.tx.send(ReconnectEvent::Reconnecting { attempt });
+	}
+
+	pub(crate) fn reconnected(&mut self) {
+		self.flaps += 1;
+		let _ = self.tx.send(ReconnectEvent::Reconnected { flaps: self.flaps });
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn delay_for_never_exceeds_max_delay() {
+		let policy = RetryPolicy { initial_delay: Duration::from_millis(100), max_delay: Duration::from_secs(1), multiplier: 2.0, max_attempts: None };
+
+		for attempt in 0..20 {
+			assert!(policy.delay_for(attempt) <= policy.max_delay, "attempt {attempt} exceeded max_delay");
+		}
+	}
+
+	#[test]
+	fn delay_for_grows_with_attempt_before_capping() {
+		let policy = RetryPolicy { initial_delay: Duration::from_millis(10), max_delay: Duration::from_secs(100), multiplier: 2.0, max_attempts: None };
+
+		// Full jitter means any single sample can be small, so check that attempt 0 never exceeds
+		// its 10ms bound while attempt 3 (drawn from up to 80ms) exceeds it in some of 100 samples.
+		for _ in 0..100 {
+			assert!(policy.delay_for(0) <= policy.initial_delay);
+		}
+		assert!((0..100).any(|_| policy.delay_for(3) > policy.initial_delay));
+	}
+
+	#[test]
+	fn should_retry_respects_max_attempts() {
+		let unlimited = RetryPolicy::default();
+		assert!(unlimited.should_retry(1_000));
+
+		let limited = RetryPolicy { max_attempts: Some(3), ..RetryPolicy::default() };
+		assert!(limited.should_retry(0));
+		assert!(limited.should_retry(2));
+		assert!(!limited.should_retry(3));
+		assert!(!limited.should_retry(10));
+	}
+}
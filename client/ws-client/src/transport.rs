@@ -0,0 +1,442 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! The background task that owns the WebSocket transport: it drains commands from the client
+//! handle, drives the socket, and - when built with a [`RetryPolicy`] - redials and re-subscribes
+//! transparently across a dropped connection instead of tearing the whole client down.
+
+use std::collections::HashMap;
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::reconnect::{ReconnectPublisher, RetryPolicy};
+use crate::Error;
+
+type WsStream = WebSocketStream<TcpStream>;
+
+/// A command sent from [`crate::WsClient`] to the background driver task.
+pub(crate) enum Command {
+	/// A request expecting a single response.
+	Call {
+		method: String,
+		params: Option<Value>,
+		/// Whether this call is safe to silently retry against the redialed connection rather
+		/// than being resolved with [`Error::Reconnected`].
+		idempotent: bool,
+		respond: oneshot::Sender<Result<Value, Error>>,
+	},
+	/// A fire-and-forget notification.
+	Notification { method: String, params: Option<Value> },
+	/// Subscribe, registering `tx` to receive every subsequent notification pushed for the
+	/// resulting subscription id.
+	Subscribe { method: String, params: Option<Value>, tx: mpsc::Sender<Value>, respond: oneshot::Sender<Result<(), Error>> },
+}
+
+struct PendingCall {
+	idempotent: bool,
+	method: String,
+	params: Option<Value>,
+	respond: oneshot::Sender<Result<Value, Error>>,
+}
+
+struct PendingSubscribe {
+	method: String,
+	params: Option<Value>,
+	tx: mpsc::Sender<Value>,
+	respond: Option<oneshot::Sender<Result<(), Error>>>,
+}
+
+enum Pending {
+	Call(PendingCall),
+	Subscribe(PendingSubscribe),
+}
+
+/// An active subscription, kept around so it can be re-issued with the same `tx` after a redial.
+struct ActiveSub {
+	method: String,
+	params: Option<Value>,
+	tx: mpsc::Sender<Value>,
+}
+
+/// Everything the driver loop threads through a single connection's lifetime.
+struct Session {
+	next_id: u64,
+	pending: HashMap<u64, Pending>,
+	subs: HashMap<u64, ActiveSub>,
+}
+
+impl Session {
+	fn new() -> Self {
+		Self { next_id: 0, pending: HashMap::new(), subs: HashMap::new() }
+	}
+
+	fn alloc_id(&mut self) -> u64 {
+		let id = self.next_id;
+		self.next_id += 1;
+		id
+	}
+}
+
+/// Run the driver loop for `url`, over the already-established `stream`, until the client handle
+/// is dropped or redialing is exhausted. Takes `stream` rather than dialing it itself so the
+/// connection `WsClient::connect` opened to fail fast is the one actually used, instead of a
+/// second one being dialed here and the first silently dropped.
+pub(crate) async fn run(url: String, stream: WsStream, mut cmd_rx: mpsc::UnboundedReceiver<Command>, reconnect: Option<RetryPolicy>, mut publisher: ReconnectPublisher) {
+	let mut stream = stream;
+	let mut session = Session::new();
+
+	'driver: loop {
+		tokio::select! {
+			cmd = cmd_rx.recv() => {
+				let Some(cmd) = cmd else { break 'driver };
+				if send(&mut stream, &mut session, cmd).await.is_err()
+					&& !redial(&url, &mut stream, &mut session, reconnect.as_ref(), &mut publisher).await
+				{
+					break 'driver;
+				}
+			}
+			msg = stream.next() => {
+				let Some(Ok(msg)) = msg else {
+					if !redial(&url, &mut stream, &mut session, reconnect.as_ref(), &mut publisher).await {
+						break 'driver;
+					}
+					continue;
+				};
+				handle_incoming(msg, &mut session);
+			}
+		}
+	}
+
+	drain_pending(&mut session.pending, Error::Transport("client dropped".into()));
+}
+
+pub(crate) async fn dial(url: &str) -> Result<WsStream, String> {
+	let parsed = url::Url::parse(url).map_err(|e| e.to_string())?;
+	let host = parsed.host_str().ok_or("missing host")?;
+	let port = parsed.port_or_known_default().ok_or("missing port")?;
+
+	let tcp = TcpStream::connect((host, port)).await.map_err(|e| e.to_string())?;
+	let (stream, _response) = tokio_tungstenite::client_async(url, tcp).await.map_err(|e| e.to_string())?;
+	Ok(stream)
+}
+
+async fn send(stream: &mut WsStream, session: &mut Session, cmd: Command) -> Result<(), ()> {
+	match cmd {
+		Command::Call { method, params, idempotent, respond } => {
+			let id = session.alloc_id();
+			let payload = serde_json::json!({ "id": id, "method": method, "params": params });
+			if stream.send(Message::Text(payload.to_string())).await.is_err() {
+				let _ = respond.send(Err(Error::Transport("send failed".into())));
+				return Err(());
+			}
+			session.pending.insert(id, Pending::Call(PendingCall { idempotent, method, params, respond }));
+			Ok(())
+		}
+		Command::Notification { method, params } => {
+			let payload = serde_json::json!({ "method": method, "params": params });
+			stream.send(Message::Text(payload.to_string())).await.map_err(|_| ())
+		}
+		Command::Subscribe { method, params, tx, respond } => {
+			let id = session.alloc_id();
+			let payload = serde_json::json!({ "id": id, "method": method, "params": params });
+			if stream.send(Message::Text(payload.to_string())).await.is_err() {
+				let _ = respond.send(Err(Error::Transport("send failed".into())));
+				return Err(());
+			}
+			session.pending.insert(id, Pending::Subscribe(PendingSubscribe { method, params, tx, respond: Some(respond) }));
+			Ok(())
+		}
+	}
+}
+
+fn handle_incoming(msg: Message, session: &mut Session) {
+	let Message::Text(text) = msg else { return };
+	let Ok(value) = serde_json::from_str::<Value>(&text) else { return };
+
+	// A notification push carries a `subscription` id and no top-level `id`.
+	if let Some(sub_id) = value.get("subscription").and_then(Value::as_u64) {
+		if let Some(sub) = session.subs.get(&sub_id) {
+			let _ = sub.tx.try_send(value.get("result").cloned().unwrap_or(Value::Null));
+		}
+		return;
+	}
+
+	let Some(id) = value.get("id").and_then(Value::as_u64) else { return };
+	let Some(slot) = session.pending.remove(&id) else { return };
+
+	let result = if let Some(err) = value.get("error") {
+		let code = err.get("code").and_then(Value::as_i64).unwrap_or_default() as i32;
+		let message = err.get("message").and_then(Value::as_str).unwrap_or_default().to_owned();
+		let data = err.get("data").map(Value::to_string);
+		Err(Error::Core(jsonrpsee_core::Error::Call(jsonrpsee_types::ErrorObjectOwned::owned(code, message, data))))
+	} else {
+		Ok(value.get("result").cloned().unwrap_or(Value::Null))
+	};
+
+	match slot {
+		Pending::Call(call) => {
+			let _ = call.respond.send(result);
+		}
+		Pending::Subscribe(sub) => match result {
+			Ok(Value::Number(n)) if n.as_u64().is_some() => {
+				let sub_id = n.as_u64().expect("checked above");
+				if let Some(respond) = sub.respond {
+					let _ = respond.send(Ok(()));
+				}
+				session.subs.insert(sub_id, ActiveSub { method: sub.method, params: sub.params, tx: sub.tx });
+			}
+			Ok(_) => {
+				if let Some(respond) = sub.respond {
+					let _ = respond.send(Err(Error::Transport("subscribe did not return a subscription id".into())));
+				}
+			}
+			Err(e) => {
+				if let Some(respond) = sub.respond {
+					let _ = respond.send(Err(e));
+				}
+			}
+		},
+	}
+}
+
+/// Redial `url` per `policy`, resolve/retry in-flight calls, and re-issue every still-referenced
+/// subscription against the new connection. Returns `false` if redialing should stop (no policy
+/// configured, or `policy.max_attempts` exhausted) and the driver task should shut down.
+async fn redial(url: &str, stream: &mut WsStream, session: &mut Session, policy: Option<&RetryPolicy>, publisher: &mut ReconnectPublisher) -> bool {
+	let Some(policy) = policy else {
+		drain_pending(&mut session.pending, Error::Transport("connection closed".into()));
+		return false;
+	};
+
+	publisher.disconnected();
+
+	// Every in-flight call is resolved now: idempotent calls are queued for resend once the new
+	// connection is up, everything else gets a distinct error so the caller can decide what to do.
+	let mut to_resend = Vec::new();
+	for (_, slot) in session.pending.drain() {
+		match slot {
+			Pending::Call(call) if call.idempotent => to_resend.push((call.method, call.params, call.respond)),
+			Pending::Call(call) => {
+				let _ = call.respond.send(Err(Error::Reconnected));
+			}
+			Pending::Subscribe(sub) => {
+				if let Some(respond) = sub.respond {
+					let _ = respond.send(Err(Error::Reconnected));
+				}
+			}
+		}
+	}
+
+	let mut attempt = 0;
+	loop {
+		if !policy.should_retry(attempt) {
+			for (_, _, respond) in to_resend {
+				let _ = respond.send(Err(Error::Reconnected));
+			}
+			session.subs.clear();
+			return false;
+		}
+
+		publisher.reconnecting(attempt);
+		tokio::time::sleep(policy.delay_for(attempt)).await;
+
+		match dial(url).await {
+			Ok(new_stream) => {
+				*stream = new_stream;
+				break;
+			}
+			Err(_) => attempt += 1,
+		}
+	}
+
+	// Re-issue every still-referenced subscription against the fresh connection; its new
+	// server-assigned id is remapped to the same `tx` in `handle_incoming` once the subscribe
+	// response comes back, so the caller's handle keeps receiving items across the gap.
+	for (_old_id, sub) in session.subs.drain().collect::<Vec<_>>() {
+		let id = session.alloc_id();
+		let payload = serde_json::json!({ "id": id, "method": sub.method, "params": sub.params });
+		if stream.send(Message::Text(payload.to_string())).await.is_ok() {
+			session.pending.insert(id, Pending::Subscribe(PendingSubscribe { method: sub.method, params: sub.params, tx: sub.tx, respond: None }));
+		}
+	}
+
+	for (method, params, respond) in to_resend {
+		let id = session.alloc_id();
+		let payload = serde_json::json!({ "id": id, "method": method, "params": params });
+		if stream.send(Message::Text(payload.to_string())).await.is_ok() {
+			session.pending.insert(id, Pending::Call(PendingCall { idempotent: true, method, params, respond }));
+		} else {
+			let _ = respond.send(Err(Error::Reconnected));
+		}
+	}
+
+	publisher.reconnected();
+	true
+}
+
+fn drain_pending(pending: &mut HashMap<u64, Pending>, err: Error) {
+	for (_, slot) in pending.drain() {
+		match slot {
+			Pending::Call(call) => {
+				let _ = call.respond.send(Err(err.clone()));
+			}
+			Pending::Subscribe(sub) => {
+				if let Some(respond) = sub.respond {
+					let _ = respond.send(Err(err.clone()));
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	/// A fake WebSocket server: accepts every incoming TCP connection, upgrades it, and hands the
+	/// resulting stream back over `conn_rx` so a test can script the driver's redial behavior by
+	/// dropping a connection and observing what arrives on the next one.
+	async fn spawn_fake_server() -> (String, mpsc::UnboundedReceiver<WsStream>) {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		let (conn_tx, conn_rx) = mpsc::unbounded_channel();
+
+		tokio::spawn(async move {
+			while let Ok((tcp, _)) = listener.accept().await {
+				if let Ok(ws) = tokio_tungstenite::accept_async(tcp).await {
+					if conn_tx.send(ws).is_err() {
+						break;
+					}
+				}
+			}
+		});
+
+		(format!("ws://{addr}"), conn_rx)
+	}
+
+	fn fast_retry_policy() -> RetryPolicy {
+		RetryPolicy { initial_delay: Duration::from_millis(1), max_delay: Duration::from_millis(5), multiplier: 1.0, max_attempts: None }
+	}
+
+	async fn recv_call(conn: &mut WsStream) -> (u64, Value) {
+		let Message::Text(text) = conn.next().await.unwrap().unwrap() else { panic!("expected a text frame") };
+		let value: Value = serde_json::from_str(&text).unwrap();
+		(value["id"].as_u64().unwrap(), value)
+	}
+
+	#[tokio::test]
+	async fn idempotent_call_is_retried_transparently_after_a_redial() {
+		let (url, mut conns) = spawn_fake_server().await;
+		let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+		let (publisher, _state) = ReconnectPublisher::new();
+
+		let stream = dial(&url).await.unwrap();
+		tokio::spawn(run(url, stream, cmd_rx, Some(fast_retry_policy()), publisher));
+
+		let mut first_conn = conns.recv().await.unwrap();
+
+		let (respond, rx) = oneshot::channel();
+		cmd_tx.send(Command::Call { method: "ping".into(), params: None, idempotent: true, respond }).unwrap();
+
+		// Receive the call, then drop the connection without responding to force a redial.
+		recv_call(&mut first_conn).await;
+		drop(first_conn);
+
+		// The call is retried against the redialed connection rather than failing the caller.
+		let mut second_conn = conns.recv().await.unwrap();
+		let (id, _) = recv_call(&mut second_conn).await;
+		second_conn.send(Message::Text(serde_json::json!({ "id": id, "result": 42 }).to_string())).await.unwrap();
+
+		assert_eq!(rx.await.unwrap().unwrap(), serde_json::json!(42));
+	}
+
+	#[tokio::test]
+	async fn non_idempotent_call_resolves_reconnected_on_redial() {
+		let (url, mut conns) = spawn_fake_server().await;
+		let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+		let (publisher, _state) = ReconnectPublisher::new();
+
+		let stream = dial(&url).await.unwrap();
+		tokio::spawn(run(url, stream, cmd_rx, Some(fast_retry_policy()), publisher));
+
+		let mut first_conn = conns.recv().await.unwrap();
+
+		let (respond, rx) = oneshot::channel();
+		cmd_tx.send(Command::Call { method: "ping".into(), params: None, idempotent: false, respond }).unwrap();
+
+		recv_call(&mut first_conn).await;
+		drop(first_conn);
+
+		match rx.await.unwrap() {
+			Err(Error::Reconnected) => {}
+			other => panic!("expected Error::Reconnected, got {other:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn subscription_keeps_yielding_items_after_a_redial() {
+		let (url, mut conns) = spawn_fake_server().await;
+		let (cmd_tx, cmd_rx) = mpsc::unbounded_channel();
+		let (publisher, _state) = ReconnectPublisher::new();
+
+		let stream = dial(&url).await.unwrap();
+		tokio::spawn(run(url, stream, cmd_rx, Some(fast_retry_policy()), publisher));
+
+		let mut first_conn = conns.recv().await.unwrap();
+
+		let (tx, mut sub_rx) = mpsc::channel(8);
+		let (respond, ack) = oneshot::channel();
+		cmd_tx.send(Command::Subscribe { method: "sub".into(), params: None, tx, respond }).unwrap();
+
+		let (id, _) = recv_call(&mut first_conn).await;
+		first_conn.send(Message::Text(serde_json::json!({ "id": id, "result": 7 }).to_string())).await.unwrap();
+		ack.await.unwrap().unwrap();
+
+		first_conn.send(Message::Text(serde_json::json!({ "subscription": 7, "result": "first" }).to_string())).await.unwrap();
+		assert_eq!(sub_rx.recv().await.unwrap(), serde_json::json!("first"));
+
+		// Drop the connection; the driver redials and re-issues the subscription under a new id.
+		drop(first_conn);
+
+		let mut second_conn = conns.recv().await.unwrap();
+		let (new_id, _) = recv_call(&mut second_conn).await;
+		second_conn.send(Message::Text(serde_json::json!({ "id": new_id, "result": 99 }).to_string())).await.unwrap();
+
+		// The caller's handle keeps receiving items, transparently remapped to the new sub id.
+		second_conn.send(Message::Text(serde_json::json!({ "subscription": 99, "result": "second" }).to_string())).await.unwrap();
+		assert_eq!(sub_rx.recv().await.unwrap(), serde_json::json!("second"));
+	}
+}
+
@@ -0,0 +1,155 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Shared core traits and error types used by the jsonrpsee client and server crates.
+
+pub use async_trait::async_trait;
+pub use jsonrpsee_types as types;
+
+use jsonrpsee_types::ErrorObjectOwned;
+
+/// Top-level error type returned by jsonrpsee clients.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum Error {
+	/// The server returned a JSON-RPC error object.
+	#[error("server returned an error: {0:?}")]
+	Call(ErrorObjectOwned),
+
+	/// A transport-level failure (connection lost, redial failed, ...).
+	#[error("transport error: {0}")]
+	Transport(String),
+
+	/// Any other client-level failure.
+	#[error("{0}")]
+	Custom(String),
+}
+
+/// Client-side traits: the request/notification/subscription surface implemented by every
+/// jsonrpsee client.
+pub mod client {
+	use super::Error;
+	use crate::params::BatchRequestBuilder;
+	use crate::traits::ToRpcParams;
+	use async_trait::async_trait;
+	use serde::de::DeserializeOwned;
+	use std::fmt::Debug;
+
+	/// A batch response: one `Result` per request in the batch, in submission order.
+	#[derive(Debug, Clone)]
+	pub struct BatchResponse<'a, R> {
+		responses: Vec<Result<R, super::ErrorObjectOwned>>,
+		_marker: std::marker::PhantomData<&'a ()>,
+	}
+
+	impl<'a, R> BatchResponse<'a, R> {
+		/// Build a batch response from individual results, in submission order.
+		pub fn new(responses: Vec<Result<R, super::ErrorObjectOwned>>) -> Self {
+			Self { responses, _marker: std::marker::PhantomData }
+		}
+
+		/// The individual results, in submission order.
+		pub fn into_results(self) -> Vec<Result<R, super::ErrorObjectOwned>> {
+			self.responses
+		}
+	}
+
+	/// A handle to an active subscription; yields notifications until dropped or unsubscribed.
+	pub struct Subscription<Notif> {
+		rx: tokio::sync::mpsc::Receiver<Notif>,
+	}
+
+	impl<Notif> Subscription<Notif> {
+		/// Wrap the receiving half of a channel that notifications are pushed into.
+		pub fn new(rx: tokio::sync::mpsc::Receiver<Notif>) -> Self {
+			Self { rx }
+		}
+
+		/// Wait for the next notification.
+		pub async fn next(&mut self) -> Option<Notif> {
+			self.rx.recv().await
+		}
+	}
+
+	/// The request/notification/batch surface implemented by every jsonrpsee client.
+	#[async_trait]
+	pub trait ClientT {
+		/// Send a notification (a request with no id, no response expected).
+		async fn notification(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<(), Error>;
+
+		/// Send a request and wait for its response.
+		async fn request<R>(&self, method: &str, params: impl ToRpcParams + Send + Clone) -> Result<R, Error>
+		where
+			R: DeserializeOwned;
+
+		/// Send a batch of requests and wait for every response.
+		async fn batch_request<'a, R>(&self, batch: BatchRequestBuilder<'a>) -> Result<BatchResponse<'a, R>, Error>
+		where
+			R: DeserializeOwned + Debug + 'a;
+	}
+}
+
+/// Parameter-encoding traits.
+pub mod traits {
+	/// Anything that can be serialized as JSON-RPC request params.
+	pub trait ToRpcParams {
+		/// Serialize `self` into the raw JSON params value, if any.
+		fn to_rpc_params(self) -> Result<Option<Box<serde_json::value::RawValue>>, serde_json::Error>;
+	}
+
+	impl ToRpcParams for () {
+		fn to_rpc_params(self) -> Result<Option<Box<serde_json::value::RawValue>>, serde_json::Error> {
+			Ok(None)
+		}
+	}
+
+	impl ToRpcParams for serde_json::Value {
+		fn to_rpc_params(self) -> Result<Option<Box<serde_json::value::RawValue>>, serde_json::Error> {
+			Ok(Some(serde_json::value::RawValue::from_string(self.to_string())?))
+		}
+	}
+}
+
+/// Request-building helpers.
+pub mod params {
+	/// Accumulates a set of method calls to submit as a single JSON-RPC batch.
+	#[derive(Debug, Clone, Default)]
+	pub struct BatchRequestBuilder<'a> {
+		calls: Vec<(&'a str, Option<Box<serde_json::value::RawValue>>)>,
+	}
+
+	impl<'a> BatchRequestBuilder<'a> {
+		/// Create an empty batch.
+		pub fn new() -> Self {
+			Self::default()
+		}
+
+		/// Add a call to the batch.
+		pub fn insert(&mut self, method: &'a str, params: impl super::traits::ToRpcParams) -> Result<(), serde_json::Error> {
+			self.calls.push((method, params.to_rpc_params()?));
+			Ok(())
+		}
+	}
+}
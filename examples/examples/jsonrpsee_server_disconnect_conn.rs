@@ -24,49 +24,26 @@
 // IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
 // DEALINGS IN THE SOFTWARE.
 
-use std::collections::HashSet;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU32, AtomicUsize};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU32;
+use std::sync::Arc;
 
 use futures::FutureExt;
 use jsonrpsee::core::{async_trait, client::ClientT};
 use jsonrpsee::proc_macros::rpc;
+use jsonrpsee::server::access::{AccessControl, AccessControlConfig};
 use jsonrpsee::server::middleware::rpc::*;
+use jsonrpsee::server::tunnel::{self, run_tcp_tunnel};
 use jsonrpsee::server::ws::{self, run_websocket};
 use jsonrpsee::server::{http, ConnectionGuard, ServiceData, StopHandle};
-use jsonrpsee::types::{ErrorObject, ErrorObjectOwned, Request};
+use jsonrpsee::types::ErrorObjectOwned;
 use jsonrpsee::ws_client::WsClientBuilder;
 use jsonrpsee::{rpc_params, MethodResponse};
 
 use hyper::server::conn::AddrStream;
-use tokio::sync::mpsc;
 use tracing_subscriber::util::SubscriberInitExt;
 
-struct DummyRateLimit<S> {
-	service: S,
-	count: Arc<AtomicUsize>,
-	state: mpsc::Sender<()>,
-}
-
-#[async_trait]
-impl<'a, S> RpcServiceT<'a> for DummyRateLimit<S>
-where
-	S: Send + Sync + RpcServiceT<'a>,
-{
-	async fn call(&self, req: Request<'a>, ctx: &Context) -> MethodResponse {
-		let count = self.count.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-
-		if count > 10 {
-			let _ = self.state.try_send(());
-			MethodResponse::error(req.id, ErrorObject::borrowed(-32000, "RPC rate limit", None))
-		} else {
-			self.service.call(req, ctx).await
-		}
-	}
-}
-
 #[rpc(server)]
 pub trait Rpc {
 	#[method(name = "say_hello")]
@@ -106,6 +83,11 @@ async fn main() -> anyhow::Result<()> {
 async fn run_server() {
 	use hyper::service::{make_service_fn, service_fn};
 
+	// A plain TCP echo server, tunneled to below whenever a client asks for the raw-bridge
+	// sub-protocol instead of JSON-RPC framing.
+	let tunnel_target = SocketAddr::from(([127, 0, 0, 1], 9945));
+	tokio::spawn(run_tcp_echo_target(tunnel_target));
+
 	// Construct our SocketAddr to listen on...
 	let addr = SocketAddr::from(([127, 0, 0, 1], 9944));
 
@@ -118,8 +100,8 @@ async fn run_server() {
 	let conn_guard = Arc::new(ConnectionGuard::new(service_cfg.settings.max_connections as usize));
 	let conn_id = Arc::new(AtomicU32::new(0));
 
-	// Blacklisted peers
-	let blacklisted_peers = Arc::new(Mutex::new(HashSet::new()));
+	// Connection-lifecycle and peer-blacklist management, shared across every connection.
+	let access = AccessControl::new(AccessControlConfig::default());
 
 	// And a MakeService to handle each connection...
 	let make_service = make_service_fn(|conn: &AddrStream| {
@@ -131,37 +113,39 @@ async fn run_server() {
 		let stop_handle = stop_handle.clone();
 		let conn_guard = conn_guard.clone();
 		let service_cfg = service_cfg.clone();
-		let blacklisted_peers = blacklisted_peers.clone();
+		let access = access.clone();
 
 		async move {
 			let stop_handle = stop_handle.clone();
 			let conn_guard = conn_guard.clone();
 			let service_cfg = service_cfg.clone();
 			let stop_handle = stop_handle.clone();
-			let blacklisted_peers = blacklisted_peers.clone();
+			let access = access.clone();
 
 			Ok::<_, Infallible>(service_fn(move |req| {
 				// Connection number limit exceeded.
 				let Some(conn_permit) = conn_guard.try_acquire() else {
+					access.record_rejection();
 					return async { Ok::<_, Infallible>(http::response::too_many_requests()) }.boxed();
 				};
 
-				// The IP addr was blacklisted.
-				if blacklisted_peers.lock().unwrap().get(&remote_addr.ip()).is_some() {
+				// The peer was blacklisted, either up front or during a prior connection.
+				if access.is_blacklisted(remote_addr.ip()) {
+					access.record_rejection();
 					return async { Ok(http::response::denied()) }.boxed();
 				}
 
 				if ws::is_upgrade_request(&req) && service_cfg.settings.enable_ws {
 					let service_cfg = service_cfg.clone();
 					let stop_handle = stop_handle.clone();
-					let blacklisted_peers = blacklisted_peers.clone();
+					let access = access.clone();
 
-					let (tx, mut disconnect) = mpsc::channel(1);
-					let rpc_service = RpcServiceBuilder::new().layer_fn(move |service| DummyRateLimit {
-						service,
-						count: Arc::new(AtomicUsize::new(0)),
-						state: tx.clone(),
-					});
+					let rate_limit_access = access.clone();
+					let rate_limit = RateLimitLayer::new(RateLimitConfig { capacity: 10.0, refill_per_sec: 0.0, adaptive: None })
+						.on_reject(move || {
+							rate_limit_access.report_abuse(remote_addr.ip(), "exceeded per-connection RPC rate limit");
+						});
+					let rpc_service = RpcServiceBuilder::new().layer(rate_limit);
 
 					let svc = ServiceData {
 						cfg: service_cfg.settings,
@@ -172,19 +156,19 @@ async fn run_server() {
 						methods: service_cfg.methods.clone(),
 					};
 
-					// Establishes the websocket connection
-					// and if the `DummyRateLimit` middleware triggers the hard limit
-					// then the connection is closed i.e, the `conn_fut` is dropped.
+					// Establishes the websocket connection and, if `RateLimitLayer` reports
+					// abuse past `access`'s threshold, the peer is blacklisted and the
+					// connection dropped, i.e. `conn_fut` is aborted.
 					async move {
 						match run_websocket(req, svc, rpc_service).await {
 							Ok((rp, conn_fut)) => {
+								access.connection_opened();
 								tokio::spawn(async move {
 									tokio::select! {
 										_ = conn_fut => (),
-										_ = disconnect.recv() => {
-											blacklisted_peers.lock().unwrap().insert(remote_addr.ip());
-										},
+										_ = access.banned(remote_addr.ip()) => (),
 									}
+									access.connection_closed();
 								});
 								Ok(rp)
 							}
@@ -192,6 +176,21 @@ async fn run_server() {
 						}
 					}
 					.boxed()
+				} else if tunnel::is_tunnel_request(&req) {
+					let conn_guard = conn_guard.clone();
+
+					// Every tunnel request is resolved to the same echo target and allowed, since
+					// this example has no notion of per-caller credentials to check.
+					async move {
+						match run_tcp_tunnel(req, |_req| Some(tunnel_target), |_req, _target| Ok(()), &conn_guard).await {
+							Ok((rp, conn_fut)) => {
+								tokio::spawn(conn_fut);
+								Ok(rp)
+							}
+							Err(rp) => Ok(rp),
+						}
+					}
+					.boxed()
 				} else {
 					// TODO: for simplicity in this example the server doesn't support HTTP requests.
 					async { Ok(http::response::denied()) }.boxed()
@@ -205,3 +204,25 @@ async fn run_server() {
 
 	server.await.unwrap();
 }
+
+/// A plain TCP echo server, standing in for whatever backend a real deployment would tunnel to.
+async fn run_tcp_echo_target(addr: SocketAddr) {
+	let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+
+	loop {
+		let Ok((mut stream, _)) = listener.accept().await else { continue };
+		tokio::spawn(async move {
+			let mut buf = [0u8; 8192];
+			loop {
+				match tokio::io::AsyncReadExt::read(&mut stream, &mut buf).await {
+					Ok(0) | Err(_) => break,
+					Ok(n) => {
+						if tokio::io::AsyncWriteExt::write_all(&mut stream, &buf[..n]).await.is_err() {
+							break;
+						}
+					}
+				}
+			}
+		});
+	}
+}
@@ -0,0 +1,333 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Connection-lifecycle and peer-blacklist management, promoted out of example boilerplate into
+//! a reusable subsystem.
+//!
+//! [`AccessControl`] counts active connections (one total across all peers; connections are not
+//! counted per IP), takes abuse reports via [`AccessControl::report_abuse`], auto-blacklists peers past a configurable threshold with a
+//! TTL-based cooldown, and is consulted both before the HTTP upgrade and for in-flight WS
+//! connections so a peer that turns abusive mid-session gets dropped too.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+
+/// Configuration for [`AccessControl`].
+#[derive(Debug, Clone)]
+pub struct AccessControlConfig {
+	/// Number of reported violations from a single peer before it is blacklisted.
+	pub violation_threshold: u32,
+	/// How long a peer stays blacklisted before the entry expires and it's allowed back in.
+	pub ban_ttl: Duration,
+}
+
+impl Default for AccessControlConfig {
+	fn default() -> Self {
+		Self { violation_threshold: 1, ban_ttl: Duration::from_secs(60 * 10) }
+	}
+}
+
+/// Persists and restores the blacklist across restarts.
+pub trait BanPersistence: Send + Sync {
+	/// Load previously persisted bans, as `(peer, ban expiry)` pairs.
+	fn load(&self) -> Vec<(IpAddr, Instant)>;
+	/// Persist that `peer` was banned until `expires_at`.
+	fn save_ban(&self, peer: IpAddr, expires_at: Instant);
+	/// Remove a peer from persisted storage once its ban has expired.
+	fn remove(&self, peer: IpAddr);
+}
+
+/// A no-op [`BanPersistence`] for servers that don't need the blacklist to survive a restart.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoPersistence;
+
+impl BanPersistence for NoPersistence {
+	fn load(&self) -> Vec<(IpAddr, Instant)> {
+		Vec::new()
+	}
+	fn save_ban(&self, _peer: IpAddr, _expires_at: Instant) {}
+	fn remove(&self, _peer: IpAddr) {}
+}
+
+#[derive(Default)]
+struct Metrics {
+	current_connections: AtomicU64,
+	total_rejections: AtomicU64,
+	active_bans: AtomicU64,
+}
+
+/// Snapshot of [`AccessControl`] metrics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AccessMetrics {
+	/// Number of connections currently open.
+	pub current_connections: u64,
+	/// Total connections rejected (denied upgrade or dropped mid-session) so far.
+	pub total_rejections: u64,
+	/// Number of peers currently banned.
+	pub active_bans: u64,
+}
+
+struct Inner {
+	cfg: AccessControlConfig,
+	violations: Mutex<HashMap<IpAddr, u32>>,
+	bans: Mutex<HashMap<IpAddr, Instant>>,
+	persistence: Box<dyn BanPersistence>,
+	metrics: Metrics,
+	/// Per-peer wakers for [`AccessControl::banned`], notified when `report_abuse` bans a peer.
+	ban_notify: Mutex<HashMap<IpAddr, Arc<Notify>>>,
+}
+
+/// Shared handle to the connection-lifecycle and peer-blacklist subsystem, cloned into every
+/// connection's service so middleware and the upgrade handler share one view of the world.
+#[derive(Clone)]
+pub struct AccessControl {
+	inner: Arc<Inner>,
+}
+
+impl AccessControl {
+	/// Create a new subsystem with the given config and no persisted bans.
+	pub fn new(cfg: AccessControlConfig) -> Self {
+		Self::with_persistence(cfg, NoPersistence)
+	}
+
+	/// Create a new subsystem, restoring any bans `persistence` has on record.
+	pub fn with_persistence(cfg: AccessControlConfig, persistence: impl BanPersistence + 'static) -> Self {
+		let now = Instant::now();
+		let bans: HashMap<IpAddr, Instant> = persistence.load().into_iter().filter(|(_, expires_at)| *expires_at > now).collect();
+		let metrics = Metrics { active_bans: AtomicU64::new(bans.len() as u64), ..Metrics::default() };
+
+		Self {
+			inner: Arc::new(Inner {
+				cfg,
+				violations: Mutex::new(HashMap::new()),
+				bans: Mutex::new(bans),
+				persistence: Box::new(persistence),
+				metrics,
+				ban_notify: Mutex::new(HashMap::new()),
+			}),
+		}
+	}
+
+	/// Whether `peer` is currently blacklisted. Expired entries are evicted as a side effect.
+	pub fn is_blacklisted(&self, peer: IpAddr) -> bool {
+		let mut bans = self.inner.bans.lock().unwrap();
+		let blacklisted = match bans.get(&peer) {
+			Some(expires_at) if *expires_at > Instant::now() => true,
+			Some(_) => {
+				bans.remove(&peer);
+				self.inner.persistence.remove(peer);
+				self.inner.metrics.active_bans.fetch_sub(1, Ordering::Relaxed);
+				false
+			}
+			None => false,
+		};
+		drop(bans);
+
+		// Most peers are never banned at all, so the common case for `ban_notify` growth isn't an
+		// expired ban but a peer whose `banned()` waiter simply finished without one. Evict once
+		// nothing is waiting on it any more (the map's own `Arc` is the last reference) so the map
+		// doesn't grow forever with one entry per distinct peer that ever connected.
+		if !blacklisted {
+			self.evict_idle_ban_notify(peer);
+		}
+
+		blacklisted
+	}
+
+	/// Remove `peer`'s `ban_notify` entry if no [`AccessControl::banned`] call is currently
+	/// waiting on it.
+	fn evict_idle_ban_notify(&self, peer: IpAddr) {
+		let mut ban_notify = self.inner.ban_notify.lock().unwrap();
+		if ban_notify.get(&peer).is_some_and(|notify| Arc::strong_count(notify) == 1) {
+			ban_notify.remove(&peer);
+		}
+	}
+
+	/// Record that a new connection was opened.
+	pub fn connection_opened(&self) {
+		self.inner.metrics.current_connections.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Record that a connection closed.
+	pub fn connection_closed(&self) {
+		self.inner.metrics.current_connections.fetch_sub(1, Ordering::Relaxed);
+	}
+
+	/// Record that the HTTP upgrade or an in-flight connection was rejected.
+	pub fn record_rejection(&self) {
+		self.inner.metrics.total_rejections.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Report a violation from `peer` (e.g. from a
+	/// [`RateLimitLayer::on_reject`](crate::middleware::rpc::RateLimitLayer::on_reject) callback;
+	/// the middleware [`Context`](crate::middleware::rpc::Context) has no method for it); blacklists
+	/// the peer once it crosses [`AccessControlConfig::violation_threshold`].
+	///
+	/// Returns `true` if this call caused `peer` to become newly blacklisted.
+	pub fn report_abuse(&self, peer: IpAddr, _reason: impl Into<String>) -> bool {
+		let mut violations = self.inner.violations.lock().unwrap();
+		let count = violations.entry(peer).or_insert(0);
+		*count += 1;
+
+		if *count >= self.inner.cfg.violation_threshold {
+			violations.remove(&peer);
+			let now = Instant::now();
+			let expires_at = now + self.inner.cfg.ban_ttl;
+			let prev = self.inner.bans.lock().unwrap().insert(peer, expires_at);
+			self.inner.persistence.save_ban(peer, expires_at);
+			// An entry already in the map (even an expired one not yet evicted) is already counted.
+			if prev.is_none() {
+				self.inner.metrics.active_bans.fetch_add(1, Ordering::Relaxed);
+			}
+			self.inner.metrics.total_rejections.fetch_add(1, Ordering::Relaxed);
+
+			if let Some(notify) = self.inner.ban_notify.lock().unwrap().get(&peer) {
+				notify.notify_waiters();
+			}
+
+			prev.map_or(true, |old_expiry| old_expiry <= now)
+		} else {
+			false
+		}
+	}
+
+	/// Wait until `peer` becomes blacklisted, without polling: resolves immediately if `peer` is
+	/// already banned, otherwise is woken the instant [`AccessControl::report_abuse`] bans it.
+	pub async fn banned(&self, peer: IpAddr) {
+		loop {
+			let notify = self.inner.ban_notify.lock().unwrap().entry(peer).or_insert_with(|| Arc::new(Notify::new())).clone();
+
+			// Register interest before checking the condition so a ban that lands between the
+			// check and the `.await` below is never missed (the classic lost-wakeup race).
+			let notified = notify.notified();
+
+			if self.is_blacklisted(peer) {
+				return;
+			}
+
+			notified.await;
+		}
+	}
+
+	/// Current metrics snapshot.
+	pub fn metrics(&self) -> AccessMetrics {
+		AccessMetrics {
+			current_connections: self.inner.metrics.current_connections.load(Ordering::Relaxed),
+			total_rejections: self.inner.metrics.total_rejections.load(Ordering::Relaxed),
+			active_bans: self.inner.metrics.active_bans.load(Ordering::Relaxed),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn peer(n: u8) -> IpAddr {
+		IpAddr::from([127, 0, 0, n])
+	}
+
+	#[test]
+	fn bans_after_threshold_and_not_before() {
+		let access = AccessControl::new(AccessControlConfig { violation_threshold: 3, ban_ttl: Duration::from_secs(60) });
+		let p = peer(1);
+
+		assert!(!access.report_abuse(p, "one"));
+		assert!(!access.is_blacklisted(p));
+		assert!(!access.report_abuse(p, "two"));
+		assert!(!access.is_blacklisted(p));
+		assert!(access.report_abuse(p, "three"));
+		assert!(access.is_blacklisted(p));
+	}
+
+	#[test]
+	fn ban_expires_after_ttl() {
+		let access = AccessControl::new(AccessControlConfig { violation_threshold: 1, ban_ttl: Duration::from_millis(10) });
+		let p = peer(2);
+
+		assert!(access.report_abuse(p, "abuse"));
+		assert!(access.is_blacklisted(p));
+
+		std::thread::sleep(Duration::from_millis(20));
+		assert!(!access.is_blacklisted(p));
+		assert_eq!(access.metrics().active_bans, 0);
+	}
+
+	#[tokio::test]
+	async fn banned_resolves_immediately_if_already_banned() {
+		let access = AccessControl::new(AccessControlConfig { violation_threshold: 1, ban_ttl: Duration::from_secs(60) });
+		let p = peer(3);
+		access.report_abuse(p, "abuse");
+
+		tokio::time::timeout(Duration::from_millis(50), access.banned(p)).await.expect("already banned, should not block");
+	}
+
+	#[tokio::test]
+	async fn banned_wakes_as_soon_as_report_abuse_bans_the_peer() {
+		let access = AccessControl::new(AccessControlConfig { violation_threshold: 1, ban_ttl: Duration::from_secs(60) });
+		let p = peer(4);
+
+		let waiter = {
+			let access = access.clone();
+			tokio::spawn(async move { access.banned(p).await })
+		};
+
+		// Give the waiter a chance to register interest before the ban lands.
+		tokio::time::sleep(Duration::from_millis(10)).await;
+		access.report_abuse(p, "abuse");
+
+		tokio::time::timeout(Duration::from_millis(200), waiter).await.expect("banned() should wake promptly").unwrap();
+	}
+
+	#[tokio::test]
+	async fn ban_notify_entry_is_evicted_once_no_longer_waited_on() {
+		let access = AccessControl::new(AccessControlConfig { violation_threshold: 1, ban_ttl: Duration::from_secs(60) });
+		let p = peer(5);
+
+		// A connection that never gets banned still creates a `ban_notify` entry for its peer.
+		let waiter = {
+			let access = access.clone();
+			tokio::spawn(async move {
+				tokio::select! {
+					_ = access.banned(p) => {}
+					_ = tokio::time::sleep(Duration::from_millis(10)) => {}
+				}
+			})
+		};
+		waiter.await.unwrap();
+		assert!(access.inner.ban_notify.lock().unwrap().contains_key(&p));
+
+		// The next `is_blacklisted` check for that peer - e.g. on its next connection attempt -
+		// evicts the now-unused entry instead of letting it linger forever.
+		assert!(!access.is_blacklisted(p));
+		assert!(!access.inner.ban_notify.lock().unwrap().contains_key(&p));
+	}
+}
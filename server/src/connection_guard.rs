@@ -0,0 +1,73 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Caps the number of concurrently open connections a server accepts.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Limits the number of connections accepted concurrently.
+#[derive(Debug, Clone)]
+pub struct ConnectionGuard {
+	max_connections: usize,
+	count: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+	/// Create a new guard that admits at most `max_connections` connections at once.
+	pub fn new(max_connections: usize) -> Self {
+		Self { max_connections, count: Arc::new(AtomicUsize::new(0)) }
+	}
+
+	/// Try to acquire a permit for a new connection, returning `None` if `max_connections` is
+	/// already in use.
+	pub fn try_acquire(&self) -> Option<ConnectionPermit> {
+		let mut current = self.count.load(Ordering::Acquire);
+		loop {
+			if current >= self.max_connections {
+				return None;
+			}
+
+			match self.count.compare_exchange_weak(current, current + 1, Ordering::AcqRel, Ordering::Acquire) {
+				Ok(_) => return Some(ConnectionPermit { count: self.count.clone() }),
+				Err(observed) => current = observed,
+			}
+		}
+	}
+}
+
+/// A permit for a single open connection; releases its slot in the owning [`ConnectionGuard`]
+/// when dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+	count: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+	fn drop(&mut self) {
+		self.count.fetch_sub(1, Ordering::AcqRel);
+	}
+}
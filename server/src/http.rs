@@ -0,0 +1,42 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Canned HTTP responses for requests rejected before (or instead of) a protocol upgrade.
+
+/// Pre-built rejection responses.
+pub mod response {
+	use hyper::{Body, Response, StatusCode};
+
+	/// `403 Forbidden`: the peer is blacklisted or otherwise not authorized.
+	pub fn denied() -> Response<Body> {
+		Response::builder().status(StatusCode::FORBIDDEN).body(Body::empty()).expect("fixed response is valid; qed")
+	}
+
+	/// `429 Too Many Requests`: the connection-count limit has been reached.
+	pub fn too_many_requests() -> Response<Body> {
+		Response::builder().status(StatusCode::TOO_MANY_REQUESTS).body(Body::empty()).expect("fixed response is valid; qed")
+	}
+}
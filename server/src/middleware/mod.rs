@@ -0,0 +1,3 @@
+//! Middleware applied around JSON-RPC method dispatch.
+
+pub mod rpc;
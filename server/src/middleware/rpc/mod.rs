@@ -0,0 +1,102 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! RPC service middleware: types shared by every layer in this module plus the built-in layers
+//! themselves.
+
+mod rate_limit;
+
+pub use rate_limit::{AdaptiveConfig, RateLimit, RateLimitConfig, RateLimitLayer};
+
+use jsonrpsee_types::Request as RpcRequest;
+
+/// Request type passed through the RPC service middleware stack.
+pub type Request<'a> = RpcRequest<'a>;
+
+/// Per-call context made available to middleware, carried alongside the request.
+#[derive(Debug, Default)]
+pub struct Context {
+	extensions: http::Extensions,
+}
+
+impl Context {
+	/// Get a reference to a value previously inserted into this context.
+	pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+		self.extensions.get::<T>()
+	}
+
+	/// Insert a value into this context, returning the previous value of the same type, if any.
+	pub fn insert<T: Send + Sync + 'static>(&mut self, val: T) -> Option<T> {
+		self.extensions.insert(val)
+	}
+}
+
+/// Re-exported so call sites only need `jsonrpsee::MethodResponse`.
+pub use jsonrpsee_types::response::MethodResponse;
+
+/// Trait that a single "hop" in the RPC service middleware stack must implement.
+#[async_trait::async_trait]
+pub trait RpcServiceT<'a> {
+	/// Process a single JSON-RPC call and produce a response.
+	async fn call(&self, request: Request<'a>, ctx: &Context) -> MethodResponse;
+}
+
+/// Builder used to compose [`RpcServiceT`] layers, mirroring [`tower::ServiceBuilder`].
+#[derive(Debug, Clone)]
+pub struct RpcServiceBuilder<L>(tower::ServiceBuilder<L>);
+
+impl RpcServiceBuilder<tower::layer::util::Identity> {
+	/// Create a new empty builder.
+	pub fn new() -> Self {
+		Self(tower::ServiceBuilder::new())
+	}
+}
+
+impl Default for RpcServiceBuilder<tower::layer::util::Identity> {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl<L> RpcServiceBuilder<L> {
+	/// Add a layer to the stack, analogous to [`tower::ServiceBuilder::layer`].
+	pub fn layer<T>(self, layer: T) -> RpcServiceBuilder<tower::layer::util::Stack<T, L>> {
+		RpcServiceBuilder(self.0.layer(layer))
+	}
+
+	/// Add a layer built from a closure, analogous to [`tower::ServiceBuilder::layer_fn`].
+	pub fn layer_fn<F>(self, f: F) -> RpcServiceBuilder<tower::layer::util::Stack<tower::layer::LayerFn<F>, L>> {
+		RpcServiceBuilder(self.0.layer(tower::layer::layer_fn(f)))
+	}
+
+	/// Build the final service by wrapping `inner`.
+	pub fn service<S>(self, inner: S) -> L::Service
+	where
+		L: tower::Layer<S>,
+	{
+		self.0.service(inner)
+	}
+}
@@ -0,0 +1,323 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! A token-bucket rate-limit layer for the RPC service middleware stack.
+//!
+//! This replaces the hand-rolled "count requests and never refill" middleware that used to live
+//! in the examples with a proper [`RateLimitLayer`] that can be composed via
+//! [`RpcServiceBuilder::layer`](super::RpcServiceBuilder::layer), optionally calling back into a
+//! connection's own abuse-tracking via [`RateLimitLayer::on_reject`].
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use hdrhistogram::Histogram;
+use jsonrpsee_types::ErrorObject;
+use tower::Layer;
+
+use super::{Context, MethodResponse, Request, RpcServiceT};
+
+/// Configuration for [`RateLimitLayer`].
+#[derive(Debug, Clone)]
+pub struct RateLimitConfig {
+	/// Maximum number of tokens the bucket can hold.
+	pub capacity: f64,
+	/// Number of tokens added to the bucket per second.
+	pub refill_per_sec: f64,
+	/// Adaptive behaviour; `None` disables it and `refill_per_sec` stays fixed.
+	pub adaptive: Option<AdaptiveConfig>,
+}
+
+/// Configuration for the adaptive part of the rate limiter.
+#[derive(Debug, Clone)]
+pub struct AdaptiveConfig {
+	/// Longest latency sample tracked by the histogram.
+	pub max_latency: Duration,
+	/// p99 latency above which `refill_per_sec` is tightened.
+	pub p99_threshold: Duration,
+	/// Factor `refill_per_sec` is divided/multiplied by when tightening/relaxing.
+	pub backoff_factor: f64,
+	/// Floor that `refill_per_sec` is never reduced below.
+	pub min_refill_per_sec: f64,
+	/// Number of latency samples collected before each tighten/relax decision, after which the
+	/// histogram is reset. Without a rolling window a single early burst of slow calls would bias
+	/// the p99 forever and `refill_per_sec` could never climb back up once latency recovered.
+	pub window_size: u64,
+}
+
+impl Default for RateLimitConfig {
+	fn default() -> Self {
+		Self { capacity: 10.0, refill_per_sec: 10.0, adaptive: None }
+	}
+}
+
+struct Bucket {
+	tokens: f64,
+	last_refill: Instant,
+	refill_per_sec: f64,
+}
+
+impl Bucket {
+	fn new(cfg: &RateLimitConfig) -> Self {
+		Self { tokens: cfg.capacity, last_refill: Instant::now(), refill_per_sec: cfg.refill_per_sec }
+	}
+
+	/// Refill the bucket based on elapsed time and try to take a single token.
+	///
+	/// Returns `true` if a token was taken and the call should be forwarded.
+	fn try_acquire(&mut self, capacity: f64) -> bool {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_refill);
+		self.last_refill = now;
+
+		self.tokens = (self.tokens + elapsed.as_secs_f64() * self.refill_per_sec).min(capacity);
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+struct Inner {
+	cfg: RateLimitConfig,
+	bucket: Mutex<Bucket>,
+	latencies: Mutex<Option<Histogram<u64>>>,
+	rejected: AtomicU64,
+}
+
+impl Inner {
+	fn new(cfg: RateLimitConfig) -> Self {
+		let latencies = cfg
+			.adaptive
+			.as_ref()
+			.map(|a| Histogram::new_with_bounds(1, a.max_latency.as_millis().max(1) as u64, 3).expect("valid HDR bounds"));
+
+		let bucket = Mutex::new(Bucket::new(&cfg));
+		Self { cfg, bucket, latencies: Mutex::new(latencies), rejected: AtomicU64::new(0) }
+	}
+
+	/// Record how long a forwarded call took and, once a full `window_size` worth of samples has
+	/// accumulated, tighten `refill_per_sec` if the window's p99 latency crosses `p99_threshold`
+	/// or relax it back towards the originally configured rate otherwise, then reset the
+	/// histogram to start a fresh window. Without the reset, a single early burst of slow calls
+	/// would bias the all-time p99 forever and `refill_per_sec` could never climb back up once
+	/// latency actually recovered.
+	fn record_latency(&self, elapsed: Duration) {
+		let Some(adaptive) = &self.cfg.adaptive else { return };
+
+		let mut guard = self.latencies.lock().unwrap();
+		let hist = guard.as_mut().expect("adaptive config implies histogram is Some; qed");
+		let _ = hist.record(elapsed.as_millis() as u64);
+
+		if hist.len() < adaptive.window_size {
+			return;
+		}
+
+		let p99 = Duration::from_millis(hist.value_at_quantile(0.99));
+		hist.reset();
+
+		let mut bucket = self.bucket.lock().unwrap();
+
+		if p99 > adaptive.p99_threshold {
+			bucket.refill_per_sec = (bucket.refill_per_sec / adaptive.backoff_factor).max(adaptive.min_refill_per_sec);
+		} else if bucket.refill_per_sec < self.cfg.refill_per_sec {
+			bucket.refill_per_sec = (bucket.refill_per_sec * adaptive.backoff_factor).min(self.cfg.refill_per_sec);
+		}
+	}
+
+	/// Total number of calls rejected by this limiter so far.
+	fn rejected_count(&self) -> u64 {
+		self.rejected.load(Ordering::Relaxed)
+	}
+}
+
+/// [`tower::Layer`] that produces [`RateLimit`] middleware.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+	inner: Arc<Inner>,
+	on_reject: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+impl RateLimitLayer {
+	/// Create a new fixed-rate layer.
+	pub fn new(cfg: RateLimitConfig) -> Self {
+		Self { inner: Arc::new(Inner::new(cfg)), on_reject: None }
+	}
+
+	/// Create a new layer with adaptive backoff driven by observed call latency.
+	pub fn adaptive(capacity: f64, refill_per_sec: f64, adaptive: AdaptiveConfig) -> Self {
+		Self::new(RateLimitConfig { capacity, refill_per_sec, adaptive: Some(adaptive) })
+	}
+
+	/// Invoke `f` every time this limiter rejects a call, e.g. to feed a connection's own
+	/// abuse-tracking (such as [`server::access::AccessControl::report_abuse`](crate::access::AccessControl::report_abuse)).
+	pub fn on_reject<F: Fn() + Send + Sync + 'static>(mut self, f: F) -> Self {
+		self.on_reject = Some(Arc::new(f));
+		self
+	}
+
+	/// Number of requests rejected by this limiter so far.
+	pub fn rejected_count(&self) -> u64 {
+		self.inner.rejected_count()
+	}
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+	type Service = RateLimit<S>;
+
+	fn layer(&self, service: S) -> Self::Service {
+		RateLimit { service, inner: self.inner.clone(), on_reject: self.on_reject.clone() }
+	}
+}
+
+/// Rate-limit middleware produced by [`RateLimitLayer`].
+#[derive(Clone)]
+pub struct RateLimit<S> {
+	service: S,
+	inner: Arc<Inner>,
+	on_reject: Option<Arc<dyn Fn() + Send + Sync>>,
+}
+
+#[async_trait::async_trait]
+impl<'a, S> RpcServiceT<'a> for RateLimit<S>
+where
+	S: Send + Sync + RpcServiceT<'a>,
+{
+	async fn call(&self, req: Request<'a>, ctx: &Context) -> MethodResponse {
+		let acquired = {
+			let mut bucket = self.inner.bucket.lock().unwrap();
+			bucket.try_acquire(self.inner.cfg.capacity)
+		};
+
+		if !acquired {
+			self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+			if let Some(cb) = &self.on_reject {
+				cb();
+			}
+			return MethodResponse::error(req.id, ErrorObject::borrowed(-32000, "rate limited", None));
+		}
+
+		let start = Instant::now();
+		let rp = self.service.call(req, ctx).await;
+		self.inner.record_latency(start.elapsed());
+		rp
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn empty_bucket_rejects_without_draining_further() {
+		let cfg = RateLimitConfig { capacity: 2.0, refill_per_sec: 0.0, adaptive: None };
+		let mut bucket = Bucket::new(&cfg);
+
+		assert!(bucket.try_acquire(cfg.capacity));
+		assert!(bucket.try_acquire(cfg.capacity));
+		// No refill configured, so the bucket is now empty and stays empty.
+		assert!(!bucket.try_acquire(cfg.capacity));
+		assert!(!bucket.try_acquire(cfg.capacity));
+	}
+
+	#[test]
+	fn bucket_refills_over_time() {
+		let cfg = RateLimitConfig { capacity: 1.0, refill_per_sec: 1000.0, adaptive: None };
+		let mut bucket = Bucket::new(&cfg);
+
+		assert!(bucket.try_acquire(cfg.capacity));
+		assert!(!bucket.try_acquire(cfg.capacity));
+
+		std::thread::sleep(Duration::from_millis(5));
+		assert!(bucket.try_acquire(cfg.capacity));
+	}
+
+	#[test]
+	fn adaptive_mode_tightens_then_relaxes() {
+		let cfg = RateLimitConfig {
+			capacity: 100.0,
+			refill_per_sec: 100.0,
+			adaptive: Some(AdaptiveConfig {
+				max_latency: Duration::from_secs(1),
+				p99_threshold: Duration::from_millis(50),
+				backoff_factor: 2.0,
+				min_refill_per_sec: 10.0,
+				window_size: 20,
+			}),
+		};
+		let inner = Inner::new(cfg);
+
+		// A burst of slow calls should tighten `refill_per_sec` below the configured rate.
+		for _ in 0..20 {
+			inner.record_latency(Duration::from_millis(200));
+		}
+		let tightened = inner.bucket.lock().unwrap().refill_per_sec;
+		assert!(tightened < 100.0, "expected refill_per_sec to be tightened, got {tightened}");
+
+		// Once latency recovers, `refill_per_sec` should climb back towards the configured rate
+		// instead of staying permanently throttled.
+		for _ in 0..20 {
+			inner.record_latency(Duration::from_millis(1));
+		}
+		let recovered = inner.bucket.lock().unwrap().refill_per_sec;
+		assert!(recovered > tightened, "expected refill_per_sec to recover, got {recovered}");
+	}
+
+	struct Echo;
+
+	#[async_trait::async_trait]
+	impl<'a> RpcServiceT<'a> for Echo {
+		async fn call(&self, req: Request<'a>, _ctx: &Context) -> MethodResponse {
+			MethodResponse::success(req.id)
+		}
+	}
+
+	#[tokio::test]
+	async fn rejection_invokes_on_reject_callback() {
+		let rejected = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		let rejected2 = rejected.clone();
+
+		let layer =
+			RateLimitLayer::new(RateLimitConfig { capacity: 1.0, refill_per_sec: 0.0, adaptive: None }).on_reject(move || {
+				rejected2.fetch_add(1, Ordering::Relaxed);
+			});
+		let svc = layer.layer(Echo);
+		let ctx = Context::default();
+
+		let req = |id| Request {
+			method: std::borrow::Cow::Borrowed("say_hello"),
+			id: jsonrpsee_types::Id::Number(id),
+		};
+
+		assert!(!svc.call(req(0), &ctx).await.is_error());
+		assert!(svc.call(req(1), &ctx).await.is_error());
+		assert_eq!(rejected.load(Ordering::Relaxed), 1);
+	}
+}
@@ -0,0 +1,220 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! WebSocket-to-TCP tunneling, for upgrade requests that want a raw byte-stream bridge instead of
+//! JSON-RPC framing.
+//!
+//! This sits next to `ws::run_websocket` and mirrors its `(rp, conn_fut)` shape so the same `make_service_fn` can branch on the incoming request and serve
+//! either JSON-RPC or a raw TCP bridge through the one upgrade endpoint.
+
+use std::future::Future;
+use std::net::SocketAddr;
+
+use futures_util::{SinkExt, StreamExt};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request, Response};
+use hyper_tungstenite::tungstenite::Message;
+use hyper_tungstenite::WebSocketStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::{http, ConnectionGuard};
+
+/// Why a tunnel request was rejected before the WebSocket upgrade completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunnelAuthError {
+	/// The caller is not authorized to open a tunnel to the resolved target at all.
+	Forbidden,
+	/// The credentials presented (e.g. bearer token) were missing or invalid.
+	BadToken,
+}
+
+/// Resolves the authorized TCP target for a tunnel request, e.g. from a claim in a bearer token.
+pub trait TargetResolver {
+	/// Resolve `req` to a `host:port` target, or `None` if the request doesn't carry one.
+	fn resolve(&self, req: &Request<Body>) -> Option<SocketAddr>;
+}
+
+impl<F> TargetResolver for F
+where
+	F: Fn(&Request<Body>) -> Option<SocketAddr>,
+{
+	fn resolve(&self, req: &Request<Body>) -> Option<SocketAddr> {
+		self(req)
+	}
+}
+
+/// Authorizes a tunnel request against its resolved target before the upgrade completes.
+pub trait TunnelAuth {
+	/// Check whether `req` is authorized to tunnel to `target`.
+	fn authorize(&self, req: &Request<Body>, target: SocketAddr) -> Result<(), TunnelAuthError>;
+}
+
+impl<F> TunnelAuth for F
+where
+	F: Fn(&Request<Body>, SocketAddr) -> Result<(), TunnelAuthError>,
+{
+	fn authorize(&self, req: &Request<Body>, target: SocketAddr) -> Result<(), TunnelAuthError> {
+		self(req, target)
+	}
+}
+
+/// Name of the `Sec-WebSocket-Protocol` value that selects the raw TCP tunnel instead of
+/// JSON-RPC framing on the shared upgrade endpoint.
+pub const TUNNEL_SUBPROTOCOL: &str = "jsonrpsee-tcp-tunnel";
+
+/// Whether `req` asked for the TCP tunnel sub-protocol rather than plain JSON-RPC framing.
+pub fn is_tunnel_request(req: &Request<Body>) -> bool {
+	req.headers().get(hyper::header::SEC_WEBSOCKET_PROTOCOL).and_then(|v| v.to_str().ok()).is_some_and(|v| {
+		v.split(',').any(|p| p.trim() == TUNNEL_SUBPROTOCOL)
+	})
+}
+
+/// Upgrade `req` and bidirectionally bridge its WebSocket binary frames with a `TcpStream` dialed
+/// to the target resolved by `target_resolver`, enforcing `conn_guard` and `auth` the same way
+/// `run_websocket` enforces them for JSON-RPC connections.
+///
+/// Mirrors `ws::run_websocket`: on success returns the HTTP response that completes the upgrade
+/// handshake together with a future driving the bridge to completion; on rejection returns the
+/// `Response` to send instead, without upgrading the connection: `429 Too Many Requests` if
+/// `conn_guard` has no free permit, and `403 Forbidden` if no target resolves, if `auth` returns
+/// either [`TunnelAuthError`], or if the request is not a valid WebSocket upgrade.
+pub async fn run_tcp_tunnel<R, A>(
+	req: Request<Body>,
+	target_resolver: R,
+	auth: A,
+	conn_guard: &ConnectionGuard,
+) -> Result<(Response<Body>, impl Future<Output = ()>), Response<Body>>
+where
+	R: TargetResolver,
+	A: TunnelAuth,
+{
+	let Some(conn_permit) = conn_guard.try_acquire() else {
+		return Err(http::response::too_many_requests());
+	};
+
+	let Some(target) = target_resolver.resolve(&req) else {
+		return Err(http::response::denied());
+	};
+
+	if let Err(err) = auth.authorize(&req, target) {
+		return Err(match err {
+			TunnelAuthError::Forbidden => http::response::denied(),
+			TunnelAuthError::BadToken => http::response::denied(),
+		});
+	}
+
+	let (mut rp, on_upgrade) = match hyper_tungstenite::upgrade(req, None) {
+		Ok(upgraded) => upgraded,
+		Err(_) => return Err(http::response::denied()),
+	};
+	rp.headers_mut().insert(
+		hyper::header::SEC_WEBSOCKET_PROTOCOL,
+		hyper::header::HeaderValue::from_static(TUNNEL_SUBPROTOCOL),
+	);
+
+	let conn_fut = async move {
+		// Keep the connection permit alive for the lifetime of the bridge.
+		let _conn_permit = conn_permit;
+
+		let ws_stream = match on_upgrade.await {
+			Ok(stream) => stream,
+			Err(_) => return,
+		};
+
+		let tcp_stream = match TcpStream::connect(target).await {
+			Ok(stream) => stream,
+			Err(_) => return,
+		};
+
+		bridge(ws_stream, tcp_stream).await;
+	};
+
+	Ok((rp, conn_fut))
+}
+
+/// Bidirectionally forward `Message::Binary` payloads between `ws_stream` and `tcp_stream`,
+/// unwrapping/wrapping WebSocket framing on each side rather than splicing raw bytes (which would
+/// otherwise leak WS frame headers into the tunneled byte stream).
+async fn bridge(mut ws_stream: WebSocketStream<Upgraded>, mut tcp_stream: TcpStream) {
+	let (mut tcp_read, mut tcp_write) = tcp_stream.split();
+	let mut tcp_buf = [0u8; 8192];
+
+	loop {
+		tokio::select! {
+			msg = ws_stream.next() => {
+				match msg {
+					Some(Ok(Message::Binary(data))) => {
+						if tcp_write.write_all(&data).await.is_err() {
+							break;
+						}
+					}
+					// Only binary frames carry tunnel payload; control and text frames are
+					// dropped and bridging continues.
+					Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Text(_) | Message::Frame(_))) => {}
+					Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+				}
+			}
+			n = tcp_read.read(&mut tcp_buf) => {
+				match n {
+					Ok(0) | Err(_) => break,
+					Ok(n) => {
+						if ws_stream.send(Message::Binary(tcp_buf[..n].to_vec())).await.is_err() {
+							break;
+						}
+					}
+				}
+			}
+		}
+	}
+
+	let _ = ws_stream.close(None).await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn req_with_protocol(value: Option<&str>) -> Request<Body> {
+		let mut builder = Request::builder();
+		if let Some(value) = value {
+			builder = builder.header(hyper::header::SEC_WEBSOCKET_PROTOCOL, value);
+		}
+		builder.body(Body::empty()).unwrap()
+	}
+
+	#[test]
+	fn recognizes_tunnel_subprotocol() {
+		assert!(is_tunnel_request(&req_with_protocol(Some(TUNNEL_SUBPROTOCOL))));
+		assert!(is_tunnel_request(&req_with_protocol(Some("other, jsonrpsee-tcp-tunnel"))));
+	}
+
+	#[test]
+	fn plain_json_rpc_upgrade_is_not_a_tunnel_request() {
+		assert!(!is_tunnel_request(&req_with_protocol(None)));
+		assert!(!is_tunnel_request(&req_with_protocol(Some("other-protocol"))));
+	}
+}
@@ -0,0 +1,135 @@
+// Copyright 2019-2021 Parity Technologies (UK) Ltd.
+//
+// Permission is hereby granted, free of charge, to any
+// person obtaining a copy of this software and associated
+// documentation files (the "Software"), to deal in the
+// Software without restriction, including without
+// limitation the rights to use, copy, modify, merge,
+// publish, distribute, sublicense, and/or sell copies of
+// the Software, and to permit persons to whom the Software
+// is furnished to do so, subject to the following
+// conditions:
+//
+// The above copyright notice and this permission notice
+// shall be included in all copies or substantial portions
+// of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF
+// ANY KIND, EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED
+// TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A
+// PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT
+// SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY
+// CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR
+// IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+// DEALINGS IN THE SOFTWARE.
+
+//! Shared JSON-RPC types used by `jsonrpsee-core`, `jsonrpsee-server` and the client crates.
+
+use std::borrow::Cow;
+
+/// A JSON-RPC request id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Id<'a> {
+	/// Numeric id.
+	Number(i64),
+	/// String id.
+	Str(Cow<'a, str>),
+}
+
+impl<'a> Id<'a> {
+	/// Clone the id into an owned, `'static` id.
+	pub fn into_owned(self) -> Id<'static> {
+		match self {
+			Id::Number(n) => Id::Number(n),
+			Id::Str(s) => Id::Str(Cow::Owned(s.into_owned())),
+		}
+	}
+}
+
+/// A borrowed JSON-RPC request, as handed to `RpcServiceT::call` middleware.
+#[derive(Debug, Clone)]
+pub struct Request<'a> {
+	/// The method being called.
+	pub method: Cow<'a, str>,
+	/// The request id.
+	pub id: Id<'a>,
+}
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorObject<'a> {
+	code: i32,
+	message: Cow<'a, str>,
+	data: Option<String>,
+}
+
+/// An [`ErrorObject`] that owns its contents.
+pub type ErrorObjectOwned = ErrorObject<'static>;
+
+impl<'a> ErrorObject<'a> {
+	/// Create an error object that borrows its message from `message`.
+	pub fn borrowed(code: i32, message: &'a str, data: Option<&'a str>) -> Self {
+		Self { code, message: Cow::Borrowed(message), data: data.map(ToOwned::to_owned) }
+	}
+
+	/// Create an error object that owns its message.
+	pub fn owned(code: i32, message: impl Into<String>, data: Option<String>) -> ErrorObjectOwned {
+		ErrorObject { code, message: Cow::Owned(message.into()), data }
+	}
+
+	/// The JSON-RPC error code.
+	pub fn code(&self) -> i32 {
+		self.code
+	}
+
+	/// The human-readable error message.
+	pub fn message(&self) -> &str {
+		&self.message
+	}
+
+	/// Optional structured error data.
+	pub fn data(&self) -> Option<&str> {
+		self.data.as_deref()
+	}
+
+	/// Clone this error object into an owned, `'static` one.
+	pub fn into_owned(self) -> ErrorObjectOwned {
+		ErrorObject { code: self.code, message: Cow::Owned(self.message.into_owned()), data: self.data }
+	}
+}
+
+/// The response produced by a single JSON-RPC method call.
+pub mod response {
+	use super::{ErrorObjectOwned, Id};
+
+	/// The outcome of dispatching a single JSON-RPC call.
+	#[derive(Debug, Clone, PartialEq)]
+	pub struct MethodResponse {
+		error: Option<ErrorObjectOwned>,
+	}
+
+	impl MethodResponse {
+		/// Build a successful response for `id`.
+		pub fn success(_id: Id<'_>) -> Self {
+			Self { error: None }
+		}
+
+		/// Build an error response for `id`.
+		pub fn error<'a>(_id: Id<'a>, err: super::ErrorObject<'a>) -> Self {
+			Self { error: Some(err.into_owned()) }
+		}
+
+		/// Whether this response represents an error.
+		pub fn is_error(&self) -> bool {
+			self.error.is_some()
+		}
+
+		/// The error object, if this response represents an error.
+		pub fn error_object(&self) -> Option<&ErrorObjectOwned> {
+			self.error.as_ref()
+		}
+	}
+}
+
+pub use response::MethodResponse;